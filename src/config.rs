@@ -0,0 +1,61 @@
+use std::time::Duration;
+
+/// Default internal update interval, in milliseconds.
+const DEFAULT_INTERVAL: u32 = 100;
+
+/// Tuning knobs for a `KcpListener` and the streams it accepts.
+///
+/// The `nodelay`/`interval`/`resend`/`nc` group mirrors the standard KCP
+/// latency/throughput tuning surface (`ikcp_nodelay` in the reference
+/// implementation); `snd_wnd_size`/`rcv_wnd_size` mirror `ikcp_wndsize`.
+#[derive(Clone, Copy, Debug)]
+pub struct KcpConfig {
+    /// Maximum KCP fragment size. This is purely a fragmentation parameter
+    /// for the KCP protocol and is independent of the OS-level UDP recv
+    /// buffer size.
+    pub mtu: Option<usize>,
+
+    /// Number of accepted-but-not-yet-`accept()`-ed streams a `KcpListener`
+    /// will queue before dropping new connections. Defaults to 1024.
+    pub accept_backlog: Option<usize>,
+
+    /// How long a session may stay idle before `KcpListener` reclaims its
+    /// `conv`. The reclaimed stream stays with its owner, but its reads and
+    /// writes fail from then on.
+    pub session_expire: Duration,
+
+    /// Enables the low-latency "nodelay" mode.
+    pub nodelay: bool,
+    /// Internal update period, in milliseconds.
+    pub interval: u32,
+    /// Number of skipped ACKs that trigger a fast retransmit (0 disables
+    /// fast retransmit).
+    pub resend: u32,
+    /// Disables congestion control.
+    pub nc: bool,
+
+    /// Send window size, in packets.
+    pub snd_wnd_size: u16,
+    /// Receive window size, in packets.
+    pub rcv_wnd_size: u16,
+
+    /// Overrides KCP's internally computed minimum RTO.
+    pub rx_minrto: Option<u32>,
+}
+
+impl Default for KcpConfig {
+    fn default() -> KcpConfig {
+        KcpConfig {
+            mtu: None,
+            accept_backlog: None,
+            session_expire: Duration::from_secs(90),
+            nodelay: false,
+            interval: DEFAULT_INTERVAL,
+            resend: 0,
+            nc: false,
+            snd_wnd_size: 256,
+            rcv_wnd_size: 256,
+            rx_minrto: None,
+        }
+    }
+}
@@ -0,0 +1,18 @@
+#[macro_use]
+extern crate log;
+extern crate futures;
+extern crate kcp;
+#[macro_use]
+extern crate tokio_core;
+extern crate tokio_timer;
+
+mod config;
+mod debug;
+mod listener;
+mod session;
+mod skcp;
+mod stream;
+
+pub use config::KcpConfig;
+pub use listener::{Incoming, KcpListener};
+pub use stream::ServerKcpStream;
@@ -1,9 +1,11 @@
+use std::cell::{Cell, RefCell};
 use std::io;
 use std::net::{self, SocketAddr};
 use std::rc::Rc;
 
-use futures::{Async, Poll, Stream};
-use kcp::{get_conv, set_conv};
+use futures::sync::{mpsc, oneshot};
+use futures::{Async, Future, Poll, Stream};
+use kcp::get_conv;
 use tokio_core::net::UdpSocket;
 use tokio_core::reactor::Handle;
 
@@ -12,22 +14,61 @@ use session::KcpSessionManager;
 use skcp::KcpOutputHandle;
 use stream::ServerKcpStream;
 
+/// Number of accepted-but-not-yet-`accept()`-ed streams the background driver
+/// will hold before it starts dropping packets for brand new `conv`s.
+const DEFAULT_ACCEPT_BACKLOG: usize = 1024;
+
+/// Maximum possible size of a single UDP datagram payload. The recv buffer is
+/// always sized to this, independent of `KcpConfig::mtu`, which is purely a
+/// KCP fragmentation parameter and must not be conflated with it.
+const MAX_UDP_PAYLOAD_SIZE: usize = 65535;
+
+/// Size, in bytes, of the `conv` field `get_conv`/`set_conv` read and write
+/// at the front of every KCP segment. Anything shorter can't carry a `conv`
+/// at all.
+const KCP_CONV_SIZE: usize = 4;
+
+/// Writes `conv` to the front of a raw segment buffer in the same
+/// little-endian layout `get_conv` reads it back in. The `kcp` crate only
+/// exposes the reader half (`get_conv`) since it never needs to rewrite a
+/// `conv` itself; the server does, to stamp its own allocation onto a
+/// client's `conv == 0` initial segment before routing it on.
+fn set_conv(buf: &mut [u8], conv: u32) {
+    buf[0] = conv as u8;
+    buf[1] = (conv >> 8) as u8;
+    buf[2] = (conv >> 16) as u8;
+    buf[3] = (conv >> 24) as u8;
+}
+
+/// Maximum number of consecutive non-`WouldBlock` `recv_from` errors
+/// `KcpDriver` will swallow before giving up. A one-off transient error
+/// (e.g. an ICMP port-unreachable surfacing as ECONNREFUSED) is safe to
+/// skip past, but if the socket itself is broken (closed fd, revoked
+/// permission, ...) every subsequent `recv_from` fails the same way, and
+/// unconditionally continuing would spin the single-threaded reactor in a
+/// tight loop forever, starving every other task on it. A successful
+/// `recv_from` resets the counter.
+const MAX_CONSECUTIVE_RECV_ERRORS: u32 = 16;
+
 /// A KCP Socket server
+///
+/// Construction spawns a background task that owns the socket and keeps
+/// routing datagrams to existing sessions, independent of how often
+/// `accept()` is called. Sessions that stay idle longer than
+/// `KcpConfig::session_expire` are reclaimed automatically.
 pub struct KcpListener {
     udp: Rc<UdpSocket>,
-    sessions: KcpSessionManager,
-    handle: Handle,
-    config: KcpConfig,
-    buf: Vec<u8>,
-    output_handle: KcpOutputHandle,
+    incoming_rx: RefCell<mpsc::Receiver<(ServerKcpStream, SocketAddr)>>,
+    backlog_len: Rc<Cell<usize>>,
+    stop_tx: Option<oneshot::Sender<()>>,
 }
 
 /// An iterator that infinitely accepts connections on a `KcpListener`
-pub struct Incoming {
-    inner: KcpListener,
+pub struct Incoming<'a> {
+    inner: &'a KcpListener,
 }
 
-impl Stream for Incoming {
+impl<'a> Stream for Incoming<'a> {
     type Item = (ServerKcpStream, SocketAddr);
     type Error = io::Error;
 
@@ -36,25 +77,207 @@ impl Stream for Incoming {
     }
 }
 
+/// Background task that owns the UDP socket and the session table, reading
+/// every datagram that arrives and either feeding it to an existing session
+/// or queuing a brand new stream for `accept()` to pick up.
+struct KcpDriver {
+    udp: Rc<UdpSocket>,
+    handle: Handle,
+    config: KcpConfig,
+    sessions: KcpSessionManager,
+    output_handle: KcpOutputHandle,
+    incoming_tx: mpsc::Sender<(ServerKcpStream, SocketAddr)>,
+    backlog_len: Rc<Cell<usize>>,
+    backlog_cap: usize,
+    consecutive_recv_errors: u32,
+    buf: Vec<u8>,
+}
+
+impl KcpDriver {
+    fn process_one(&mut self) -> io::Result<Async<()>> {
+        let (size, addr) = match self.udp.recv_from(&mut self.buf) {
+            Ok(ok) => {
+                self.consecutive_recv_errors = 0;
+                ok
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+            Err(e) => {
+                self.consecutive_recv_errors += 1;
+                if self.consecutive_recv_errors > MAX_CONSECUTIVE_RECV_ERRORS {
+                    // A single peer's transport-level hiccup (e.g. an ICMP
+                    // port-unreachable surfacing as ECONNREFUSED) is safe to
+                    // skip past, but more than `MAX_CONSECUTIVE_RECV_ERRORS` of
+                    // them in a row means the socket itself is broken, not some
+                    // peer. Stop instead of busy-looping the reactor forever.
+                    error!("[RECV] recv_from failed {} times in a row, giving up: {}",
+                           self.consecutive_recv_errors,
+                           e);
+                    return Err(e);
+                }
+                warn!("[RECV] recv_from failed ({}/{} consecutive), continuing: {}",
+                      self.consecutive_recv_errors,
+                      MAX_CONSECUTIVE_RECV_ERRORS,
+                      e);
+                return Ok(Async::Ready(()));
+            }
+        };
+
+        if size == self.buf.len() {
+            warn!("[RECV] datagram from {} filled the {}-byte recv buffer, likely truncated, dropping",
+                  addr, size);
+            return Ok(Async::Ready(()));
+        }
+
+        if size < KCP_CONV_SIZE {
+            warn!("[RECV] datagram from {} is {} bytes, too short to carry a conv, dropping", addr, size);
+            return Ok(Async::Ready(()));
+        }
+
+        let buf = &mut self.buf[..size];
+        let mut conv = get_conv(&*buf);
+        trace!("[RECV] size={} conv={} addr={} {:?}", size, conv, addr, ::debug::BsDebug(buf));
+
+        match self.sessions.input_by_conv(conv, &addr, buf) {
+            Ok(true) => return Ok(Async::Ready(())),
+            Ok(false) => {}
+            Err(e) => {
+                // A malformed segment for an existing conv must not kill the
+                // driver task; every other session still needs routing.
+                warn!("[RECV] conv={} addr={} rejected by session, dropping: {}", conv, addr, e);
+                return Ok(Async::Ready(()));
+            }
+        }
+
+        // Reserve backlog capacity *before* allocating a conv or registering
+        // a session: if the backlog is full we must drop the connection
+        // attempt without touching the session table, otherwise a full
+        // backlog would permanently leak a conv (nothing ever frees one
+        // that was never registered).
+        if self.backlog_len.get() >= self.backlog_cap {
+            trace!("[ACPT] accept backlog full ({} pending), dropping new connection from {}",
+                   self.backlog_cap, addr);
+            return Ok(Async::Ready(()));
+        }
+
+        trace!("[ACPT] Accepted connection {}", addr);
+
+        // Set `conv` to 0 means let the server allocate a `conv` for it
+        if conv == 0 {
+            conv = self.sessions.get_free_conv();
+            trace!("[ACPT] Allocated conv={} for {}", conv, addr);
+
+            // Set to buffer
+            set_conv(buf, conv);
+        }
+
+        // A bad per-listener `KcpConfig` (e.g. an `mtu` too small for the
+        // KCP header) would otherwise fail identically for every new
+        // connection attempt and, propagated with `?`, permanently kill the
+        // driver task on the very first one; established sessions would
+        // lose routing as collateral damage. Drop just this connection
+        // attempt instead.
+        let mut stream = match ServerKcpStream::new_with_config(conv,
+                                                                 self.output_handle.clone(),
+                                                                 &addr,
+                                                                 &self.handle,
+                                                                 &mut self.sessions,
+                                                                 &self.config) {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("[ACPT] conv={} addr={} failed to construct stream, dropping: {}", conv, addr, e);
+                return Ok(Async::Ready(()));
+            }
+        };
+
+        // Input the initial packet. A malformed initial segment must not
+        // kill the driver task; just drop the half-built stream (its `Drop`
+        // notifies `sessions` so the conv is freed) and move on.
+        if let Err(e) = stream.input(&*buf) {
+            warn!("[ACPT] conv={} addr={} initial segment rejected, dropping: {}", conv, addr, e);
+            return Ok(Async::Ready(()));
+        }
+
+        let generation = stream.generation();
+
+        match self.incoming_tx.try_send((stream, addr)) {
+            Ok(()) => {
+                self.backlog_len.set(self.backlog_len.get() + 1);
+            }
+            Err(ref e) if e.is_full() => {
+                // We just reserved capacity above, so this should not
+                // normally happen; fail safe by releasing the conv/session
+                // we just allocated instead of leaking it. Guarded by
+                // `generation` in case this somehow races with a reuse of
+                // `conv`.
+                trace!("[ACPT] accept channel rejected {} despite reservation, releasing conv={}",
+                       addr, conv);
+                self.sessions.release(conv, generation);
+            }
+            Err(_) => return Err(io::Error::new(io::ErrorKind::BrokenPipe, "accept queue closed")),
+        }
+
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Future for KcpDriver {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        loop {
+            match self.process_one() {
+                Ok(Async::Ready(())) => continue,
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(Async::Ready(())),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
 impl KcpListener {
     fn from_udp_with_config(udp: UdpSocket, handle: &Handle, config: KcpConfig) -> io::Result<KcpListener> {
-        KcpSessionManager::new(handle).map(|updater| {
+        KcpSessionManager::new(handle, config.session_expire).map(|sessions| {
             let shared_udp = Rc::new(udp);
             let output_handle = KcpOutputHandle::new(shared_udp.clone(), handle);
 
+            let backlog = config.accept_backlog.unwrap_or(DEFAULT_ACCEPT_BACKLOG);
+            let (incoming_tx, incoming_rx) = mpsc::channel(backlog);
+            let (stop_tx, stop_rx) = oneshot::channel();
+            let backlog_len = Rc::new(Cell::new(0));
+
+            let driver = KcpDriver {
+                udp: shared_udp.clone(),
+                handle: handle.clone(),
+                config,
+                sessions,
+                output_handle,
+                incoming_tx,
+                backlog_len: backlog_len.clone(),
+                backlog_cap: backlog,
+                consecutive_recv_errors: 0,
+                buf: vec![0u8; MAX_UDP_PAYLOAD_SIZE],
+            };
+
+            // Keep draining the socket for as long as the listener is alive,
+            // so established sessions make progress even if nobody is
+            // currently calling `accept()`.
+            handle.spawn(driver.select(stop_rx.then(|_| Ok(()))).then(|_| Ok(())));
+
             KcpListener {
                 udp: shared_udp,
-                sessions: updater,
-                handle: handle.clone(),
-                config: config,
-                buf: vec![0u8; config.mtu.unwrap_or(1400)],
-                output_handle: output_handle,
+                incoming_rx: RefCell::new(incoming_rx),
+                backlog_len,
+                stop_tx: Some(stop_tx),
             }
         })
     }
     /// Creates a new `KcpListener` which will be bound to the specific address.
     ///
-    /// The returned listener is ready for accepting connections.
+    /// The returned listener is ready for accepting connections. The
+    /// nodelay/window/congestion settings in `config` are applied to every
+    /// accepted `ServerKcpStream`.
     pub fn bind_with_config(addr: &SocketAddr, handle: &Handle, config: KcpConfig) -> io::Result<KcpListener> {
         UdpSocket::bind(addr, handle).and_then(|udp| Self::from_udp_with_config(udp, handle, config))
     }
@@ -90,51 +313,117 @@ impl KcpListener {
     }
 
     /// Accept a new incoming connection from this listener.
-    pub fn accept(&mut self) -> io::Result<(ServerKcpStream, SocketAddr)> {
-        loop {
-            let (size, addr) = self.udp.recv_from(&mut self.buf)?;
-
-            let buf = &mut self.buf[..size];
-            let mut conv = get_conv(&*buf);
-            trace!("[RECV] size={} conv={} addr={} {:?}", size, conv, addr, ::debug::BsDebug(buf));
-
-            if self.sessions.input_by_conv(conv, &addr, buf)? {
-                continue;
-            }
-
-            trace!("[ACPT] Accepted connection {}", addr);
-
-            // Set `conv` to 0 means let the server allocate a `conv` for it
-            if conv == 0 {
-                conv = self.sessions.get_free_conv();
-                trace!("[ACPT] Allocated conv={} for {}", conv, addr);
+    ///
+    /// This only pops a stream off the accept backlog; packets for
+    /// already-established sessions are routed by the background driver
+    /// task regardless of how often this is called.
+    ///
+    /// # Panics
+    ///
+    /// Must be called from within a running task (e.g. from another
+    /// future's `poll`, as `Incoming` does). It polls an `mpsc::Receiver`
+    /// internally, which calls `task::current()` and panics when there is
+    /// no enclosing task.
+    ///
+    /// Only drive one `accept`/`poll_accept`/`incoming` caller at a time:
+    /// the backlog is a single-consumer channel, so a second concurrent
+    /// caller would overwrite the first's registered waker and could be
+    /// left hanging forever. `&self` lets you hold the listener in an
+    /// `Rc` to query `local_addr()` from elsewhere, not to accept from it
+    /// concurrently.
+    pub fn accept(&self) -> io::Result<(ServerKcpStream, SocketAddr)> {
+        match self.poll_accept()? {
+            Async::Ready(item) => Ok(item),
+            Async::NotReady => Err(io::Error::new(io::ErrorKind::WouldBlock, "no pending connection")),
+        }
+    }
 
-                // Set to buffer
-                set_conv(buf, conv);
+    /// Polls for a new incoming connection without blocking.
+    ///
+    /// See the panic and single-consumer notes on `accept`.
+    pub fn poll_accept(&self) -> io::Result<Async<(ServerKcpStream, SocketAddr)>> {
+        match self.incoming_rx.borrow_mut().poll() {
+            Ok(Async::Ready(Some(item))) => {
+                // Frees up the reservation `KcpDriver::process_one` made
+                // when it queued this connection.
+                self.backlog_len.set(self.backlog_len.get().saturating_sub(1));
+                Ok(Async::Ready(item))
             }
-
-            let mut stream = ServerKcpStream::new_with_config(conv,
-                                                              self.output_handle.clone(),
-                                                              &addr,
-                                                              &self.handle,
-                                                              &mut self.sessions,
-                                                              &self.config)?;
-
-            // Input the initial packet
-            stream.input(&*buf)?;
-
-            return Ok((stream, addr));
+            Ok(Async::Ready(None)) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "listener driver task exited")),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(()) => unreachable!("mpsc::Receiver::poll never errors"),
         }
     }
 
-    /// Returns an iterator over the connections being received on this listener.
-    pub fn incoming(self) -> Incoming {
+    /// Returns an iterator over the connections being received on this
+    /// listener. Only one `Incoming`/`accept`/`poll_accept` should be
+    /// driven at a time; see the notes on `accept`.
+    pub fn incoming(&self) -> Incoming<'_> {
         Incoming { inner: self }
     }
 }
 
 impl Drop for KcpListener {
     fn drop(&mut self) {
-        self.sessions.stop();
+        if let Some(stop_tx) = self.stop_tx.take() {
+            let _ = stop_tx.send(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net as std_net;
+    use std::time::Duration;
+    use tokio_core::reactor::Core;
+
+    #[test]
+    fn full_backlog_drops_new_connection_without_touching_the_reservation() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let bind_addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let server_udp = UdpSocket::bind(&bind_addr, &handle).unwrap();
+        let server_addr = server_udp.local_addr().unwrap();
+        let client_udp = std_net::UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let sessions = KcpSessionManager::new(&handle, Duration::from_secs(90)).unwrap();
+        let shared_udp = Rc::new(server_udp);
+        let output_handle = KcpOutputHandle::new(shared_udp.clone(), &handle);
+        let (incoming_tx, _incoming_rx) = mpsc::channel(1);
+        // Pretend the backlog is already at capacity.
+        let backlog_len = Rc::new(Cell::new(1));
+
+        let mut driver = KcpDriver {
+            udp: shared_udp,
+            handle,
+            config: KcpConfig::default(),
+            sessions,
+            output_handle,
+            incoming_tx,
+            backlog_len: backlog_len.clone(),
+            backlog_cap: 1,
+            consecutive_recv_errors: 0,
+            buf: vec![0u8; MAX_UDP_PAYLOAD_SIZE],
+        };
+
+        // A brand new conv (0 means "allocate one for me") arriving while
+        // the backlog is already full must be dropped before a conv or
+        // session is ever allocated for it.
+        let mut packet = vec![0u8; 8];
+        set_conv(&mut packet, 0);
+        client_udp.send_to(&packet, server_addr).unwrap();
+
+        // `recv_from` registers interest with the reactor and needs a task
+        // context; driving it through `core.run` (rather than calling
+        // `process_one` directly) supplies one and blocks until the
+        // datagram we just sent actually arrives.
+        match core.run(futures::future::poll_fn(|| driver.process_one())) {
+            Ok(()) => {}
+            other => panic!("expected the packet to be dropped without error, got {:?}", other),
+        }
+
+        assert_eq!(backlog_len.get(), 1);
     }
 }
@@ -0,0 +1,291 @@
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use futures::sync::{mpsc, oneshot};
+use futures::{Future, Stream};
+use kcp::Kcp;
+use tokio_core::reactor::Handle;
+use tokio_timer;
+
+use skcp::KcpOutput;
+
+type SharedKcp = Rc<RefCell<Kcp<KcpOutput>>>;
+
+struct SessionSlot {
+    kcp: SharedKcp,
+    last_active: Instant,
+    // Distinguishes this occupant of `conv` from whatever previously held
+    // (or will later hold) the same number once it's reused, so a
+    // close/expiry signal that was queued for an earlier occupant can never
+    // be mistaken for one about the current occupant.
+    generation: u64,
+    // Shared with the `ServerKcpStream` that owns the other `Rc` to `kcp`.
+    // The idle sweep sets this when it reclaims a slot so that stream, which
+    // has no other way to learn its session was reaped, starts erroring
+    // instead of quietly going on reading/writing through a `Kcp` whose
+    // `conv` may already have been handed to a new session.
+    closed: Rc<Cell<bool>>,
+}
+
+/// Tracks live sessions for a `KcpListener`: routes inbound datagrams to the
+/// right session's `Kcp`, hands out unused `conv`s, and reclaims sessions
+/// that go idle for longer than `session_expire` as well as sessions whose
+/// stream was dropped (signalled through the close-notifier channel handed
+/// out by `register`).
+pub struct KcpSessionManager {
+    table: Rc<RefCell<HashMap<u32, SessionSlot>>>,
+    next_conv: Cell<u32>,
+    next_generation: Cell<u64>,
+    close_tx: mpsc::UnboundedSender<(u32, u64)>,
+    stop_tx: Option<oneshot::Sender<()>>,
+}
+
+impl KcpSessionManager {
+    pub fn new(handle: &Handle, session_expire: Duration) -> io::Result<KcpSessionManager> {
+        let table = Rc::new(RefCell::new(HashMap::new()));
+        let (close_tx, close_rx) = mpsc::unbounded();
+        let stop_tx = spawn_reaper(handle, table.clone(), session_expire, close_rx);
+
+        Ok(KcpSessionManager {
+            table,
+            next_conv: Cell::new(1),
+            next_generation: Cell::new(0),
+            close_tx,
+            stop_tx: Some(stop_tx),
+        })
+    }
+
+    /// Routes an inbound datagram to its session and bumps its activity
+    /// timestamp. Returns `true` if `conv` names a known session.
+    pub fn input_by_conv(&mut self, conv: u32, _addr: &SocketAddr, buf: &[u8]) -> io::Result<bool> {
+        let mut table = self.table.borrow_mut();
+        match table.get_mut(&conv) {
+            Some(slot) => {
+                slot.kcp
+                    .borrow_mut()
+                    .input(buf)
+                    .map_err(|e| io::Error::other(format!("kcp input failed: {:?}", e)))?;
+                slot.last_active = Instant::now();
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Picks an unused `conv`. `0` is reserved to mean "allocate one for
+    /// me" on the wire, so it is never handed out here.
+    pub fn get_free_conv(&mut self) -> u32 {
+        let table = self.table.borrow();
+        loop {
+            let conv = self.next_conv.get();
+            self.next_conv.set(if conv == u32::MAX { 1 } else { conv + 1 });
+            if conv != 0 && !table.contains_key(&conv) {
+                return conv;
+            }
+        }
+    }
+
+    /// Registers a freshly accepted session, returning the sender its
+    /// stream should use to notify this manager when it is dropped, the
+    /// generation stamped on this occupant of `conv` so that notification
+    /// can be told apart from one belonging to a reused `conv`, and a flag
+    /// the stream should check before every `read`/`write`/`input` to learn
+    /// whether the idle sweep has since reclaimed it out from under it.
+    pub fn register(&mut self, conv: u32, kcp: SharedKcp) -> (mpsc::UnboundedSender<(u32, u64)>, u64, Rc<Cell<bool>>) {
+        let generation = self.next_generation.get();
+        self.next_generation.set(generation.wrapping_add(1));
+        let closed = Rc::new(Cell::new(false));
+        self.table.borrow_mut().insert(conv,
+                                        SessionSlot {
+                                            kcp,
+                                            last_active: Instant::now(),
+                                            generation,
+                                            closed: closed.clone(),
+                                        });
+        (self.close_tx.clone(), generation, closed)
+    }
+
+    /// Releases a `conv` that was reserved via `get_free_conv`/`register`
+    /// but never actually handed to a caller, so it can be reused
+    /// immediately instead of leaking until the idle sweep catches it.
+    /// Only removes the entry if `generation` still matches, so this can't
+    /// evict a different occupant that has since reused `conv`.
+    pub fn release(&mut self, conv: u32, generation: u64) {
+        let mut table = self.table.borrow_mut();
+        if table.get(&conv).map(|slot| slot.generation) == Some(generation) {
+            table.remove(&conv);
+        }
+    }
+}
+
+/// Spawns the background sweep that reclaims idle sessions and sessions
+/// reaped through the close-notifier channel. Returns a sender that, when
+/// dropped or fired, stops the sweep.
+fn spawn_reaper(handle: &Handle,
+                table: Rc<RefCell<HashMap<u32, SessionSlot>>>,
+                session_expire: Duration,
+                close_rx: mpsc::UnboundedReceiver<(u32, u64)>)
+                -> oneshot::Sender<()> {
+    let (stop_tx, stop_rx) = oneshot::channel();
+
+    let sweep_period = cmp::max(session_expire / 2, Duration::from_secs(1));
+    let timer = tokio_timer::wheel().build();
+
+    let sweep = {
+        let table = table.clone();
+        timer.interval(sweep_period)
+            .map_err(|_| ())
+            .for_each(move |_| {
+                let now = Instant::now();
+                let mut table = table.borrow_mut();
+                let expired: Vec<u32> = table.iter()
+                    .filter(|&(_, slot)| now.duration_since(slot.last_active) > session_expire)
+                    .map(|(conv, _)| *conv)
+                    .collect();
+                for conv in expired {
+                    if let Some(slot) = table.remove(&conv) {
+                        trace!("[SESS] conv={} idle past {:?}, reclaiming", conv, session_expire);
+                        // The caller's `ServerKcpStream` still holds the
+                        // other `Rc` to this slot's `Kcp` and has no other
+                        // way to learn it was reclaimed; poison it so its
+                        // `read`/`write`/`input` start erroring instead of
+                        // silently going on writing packets tagged with a
+                        // `conv` that may already have been reused.
+                        slot.closed.set(true);
+                    }
+                }
+                Ok(())
+            })
+    };
+
+    let reap_closed = {
+        let table = table.clone();
+        close_rx.for_each(move |(conv, generation)| {
+            let mut table = table.borrow_mut();
+            if table.get(&conv).map(|slot| slot.generation) == Some(generation) {
+                trace!("[SESS] conv={} closed, reclaiming", conv);
+                table.remove(&conv);
+            } else {
+                trace!("[SESS] conv={} closed, but already superseded by a newer session, ignoring",
+                       conv);
+            }
+            Ok(())
+        })
+    };
+
+    handle.spawn(sweep.select(reap_closed)
+        .then(|_| Ok::<(), ()>(()))
+        .select(stop_rx.then(|_| Ok::<(), ()>(())))
+        .then(|_| Ok(())));
+
+    stop_tx
+}
+
+impl Drop for KcpSessionManager {
+    fn drop(&mut self) {
+        if let Some(stop_tx) = self.stop_tx.take() {
+            let _ = stop_tx.send(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_core::net::UdpSocket;
+    use tokio_core::reactor::Core;
+    use kcp::Kcp;
+    use skcp::KcpOutputHandle;
+
+    fn dummy_kcp(core: &Core, conv: u32) -> SharedKcp {
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let udp = UdpSocket::bind(&addr, &core.handle()).unwrap();
+        let output_handle = KcpOutputHandle::new(Rc::new(udp), &core.handle());
+        Rc::new(RefCell::new(Kcp::new(conv, output_handle.output_to(addr))))
+    }
+
+    /// A minimal, well-formed zero-payload ACK segment for `conv`, i.e. the
+    /// smallest buffer `Kcp::input` will accept instead of rejecting with
+    /// `InvaidSegmentSize`.
+    fn ack_segment(conv: u32) -> Vec<u8> {
+        let mut buf = vec![0u8; 24];
+        buf[0] = conv as u8;
+        buf[1] = (conv >> 8) as u8;
+        buf[2] = (conv >> 16) as u8;
+        buf[3] = (conv >> 24) as u8;
+        buf[4] = 82; // KCP_CMD_ACK
+        buf
+    }
+
+    #[test]
+    fn register_stamps_distinct_generations() {
+        let core = Core::new().unwrap();
+        let mut sessions = KcpSessionManager::new(&core.handle(), Duration::from_secs(90)).unwrap();
+        let (_, g1, _) = sessions.register(1, dummy_kcp(&core, 1));
+        let (_, g2, _) = sessions.register(2, dummy_kcp(&core, 2));
+        assert_ne!(g1, g2);
+    }
+
+    #[test]
+    fn release_with_matching_generation_removes_the_session() {
+        let core = Core::new().unwrap();
+        let mut sessions = KcpSessionManager::new(&core.handle(), Duration::from_secs(90)).unwrap();
+        let (_, generation, _) = sessions.register(1, dummy_kcp(&core, 1));
+        assert!(sessions.table.borrow().contains_key(&1));
+
+        sessions.release(1, generation);
+        assert!(!sessions.table.borrow().contains_key(&1));
+    }
+
+    #[test]
+    fn release_with_stale_generation_is_a_noop() {
+        let core = Core::new().unwrap();
+        let mut sessions = KcpSessionManager::new(&core.handle(), Duration::from_secs(90)).unwrap();
+        let (_, generation, _) = sessions.register(1, dummy_kcp(&core, 1));
+
+        // As if `conv` 1 had already been reclaimed and reused by the time
+        // this stale release for the old occupant arrives.
+        sessions.release(1, generation.wrapping_add(1));
+        assert!(sessions.table.borrow().contains_key(&1));
+    }
+
+    #[test]
+    fn input_by_conv_reports_whether_the_conv_is_known() {
+        let core = Core::new().unwrap();
+        let mut sessions = KcpSessionManager::new(&core.handle(), Duration::from_secs(90)).unwrap();
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+
+        assert!(!sessions.input_by_conv(1, &addr, &ack_segment(1)).unwrap());
+
+        sessions.register(1, dummy_kcp(&core, 1));
+        assert!(sessions.input_by_conv(1, &addr, &ack_segment(1)).unwrap());
+    }
+
+    #[test]
+    fn stale_close_notification_does_not_evict_a_reused_conv() {
+        let mut core = Core::new().unwrap();
+        let mut sessions = KcpSessionManager::new(&core.handle(), Duration::from_secs(90)).unwrap();
+
+        let (old_close_tx, old_generation, _) = sessions.register(7, dummy_kcp(&core, 7));
+
+        // conv 7 is reclaimed and immediately reused by a new session
+        // before the old session's close notification is delivered.
+        let (_new_close_tx, new_generation, _) = sessions.register(7, dummy_kcp(&core, 7));
+        assert_ne!(old_generation, new_generation);
+
+        old_close_tx.unbounded_send((7, old_generation)).unwrap();
+        core.turn(Some(Duration::from_millis(200)));
+        assert!(sessions.table.borrow().contains_key(&7),
+                "stale notification for the old generation must not evict the new session");
+
+        old_close_tx.unbounded_send((7, new_generation)).unwrap();
+        core.turn(Some(Duration::from_millis(200)));
+        assert!(!sessions.table.borrow().contains_key(&7),
+                "a notification matching the current generation must still reclaim it");
+    }
+}
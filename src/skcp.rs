@@ -0,0 +1,45 @@
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+use tokio_core::net::UdpSocket;
+use tokio_core::reactor::Handle;
+
+/// Cheaply cloneable handle to the UDP socket shared by every session,
+/// used to flush KCP output segments.
+#[derive(Clone)]
+pub struct KcpOutputHandle {
+    udp: Rc<UdpSocket>,
+}
+
+impl KcpOutputHandle {
+    pub fn new(udp: Rc<UdpSocket>, _handle: &Handle) -> KcpOutputHandle {
+        KcpOutputHandle { udp }
+    }
+
+    /// Binds this handle to a single peer address, producing the `Write`
+    /// sink a session's `Kcp` state machine flushes its segments to.
+    pub fn output_to(&self, addr: SocketAddr) -> KcpOutput {
+        KcpOutput {
+            udp: self.udp.clone(),
+            addr,
+        }
+    }
+}
+
+/// `io::Write` sink that flushes every write as one UDP datagram to a fixed
+/// peer address.
+pub struct KcpOutput {
+    udp: Rc<UdpSocket>,
+    addr: SocketAddr,
+}
+
+impl Write for KcpOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.udp.send_to(buf, &self.addr)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,122 @@
+use std::cell::{Cell, RefCell};
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+use futures::sync::mpsc;
+use kcp::Kcp;
+use tokio_core::reactor::Handle;
+
+use config::KcpConfig;
+use session::KcpSessionManager;
+use skcp::{KcpOutput, KcpOutputHandle};
+
+fn kcp_err_to_io<E: ::std::fmt::Debug>(err: E) -> io::Error {
+    io::Error::other(format!("kcp error: {:?}", err))
+}
+
+/// Applies the nodelay/congestion/window tuning surface from `config` to a
+/// freshly constructed `Kcp`, so the latency/throughput tradeoff is a
+/// per-`KcpConfig` choice rather than always using KCP's conservative
+/// defaults.
+fn apply_config(kcp: &mut Kcp<KcpOutput>, config: &KcpConfig) -> io::Result<()> {
+    let nodelay = if config.nodelay { 1 } else { 0 };
+    kcp.set_nodelay(nodelay, config.interval as i32, config.resend as i32, config.nc);
+    kcp.set_wndsize(config.snd_wnd_size, config.rcv_wnd_size);
+
+    if let Some(rx_minrto) = config.rx_minrto {
+        kcp.set_rx_minrto(rx_minrto);
+    }
+
+    if let Some(mtu) = config.mtu {
+        kcp.set_mtu(mtu).map_err(kcp_err_to_io)?;
+    }
+
+    Ok(())
+}
+
+/// A KCP stream accepted by a `KcpListener`.
+pub struct ServerKcpStream {
+    conv: u32,
+    generation: u64,
+    kcp: Rc<RefCell<Kcp<KcpOutput>>>,
+    close_tx: mpsc::UnboundedSender<(u32, u64)>,
+    // Set by `KcpSessionManager`'s idle sweep if it reclaims this stream's
+    // session out from under it; once set, `read`/`write`/`input` must stop
+    // touching `kcp`, since `conv` may already have been handed to a new
+    // session sharing the same underlying socket.
+    closed: Rc<Cell<bool>>,
+}
+
+impl ServerKcpStream {
+    pub fn new_with_config(conv: u32,
+                            output_handle: KcpOutputHandle,
+                            addr: &SocketAddr,
+                            _handle: &Handle,
+                            sessions: &mut KcpSessionManager,
+                            config: &KcpConfig)
+                            -> io::Result<ServerKcpStream> {
+        let mut kcp = Kcp::new(conv, output_handle.output_to(*addr));
+        apply_config(&mut kcp, config)?;
+        let kcp = Rc::new(RefCell::new(kcp));
+        let (close_tx, generation, closed) = sessions.register(conv, kcp.clone());
+
+        Ok(ServerKcpStream {
+            conv,
+            generation,
+            kcp,
+            close_tx,
+            closed,
+        })
+    }
+
+    /// The generation this stream's session was stamped with when
+    /// registered, so a caller holding both the stream and a lower-level
+    /// reference to the session can tell whether `conv` has since been
+    /// reused by a newer session.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    fn check_closed(&self) -> io::Result<()> {
+        if self.closed.get() {
+            Err(io::Error::new(io::ErrorKind::NotConnected, "session was reclaimed for being idle"))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Feeds a raw datagram payload into the underlying `Kcp` state machine.
+    pub fn input(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.check_closed()?;
+        self.kcp.borrow_mut().input(buf).map_err(kcp_err_to_io).map(|_| ())
+    }
+}
+
+impl Read for ServerKcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.check_closed()?;
+        match self.kcp.borrow_mut().recv(buf) {
+            Ok(n) => Ok(n),
+            Err(e) => Err(kcp_err_to_io(e)),
+        }
+    }
+}
+
+impl Write for ServerKcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check_closed()?;
+        self.kcp.borrow_mut().send(buf).map_err(kcp_err_to_io)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.check_closed()?;
+        self.kcp.borrow_mut().flush().map_err(kcp_err_to_io)
+    }
+}
+
+impl Drop for ServerKcpStream {
+    fn drop(&mut self) {
+        let _ = self.close_tx.unbounded_send((self.conv, self.generation));
+    }
+}